@@ -5,10 +5,12 @@ use crate::internal_events::kubernetes::stream as internal_events;
 use async_stream::try_stream;
 use bytes05::Buf;
 use futures::pin_mut;
-use futures::stream::Stream;
+use futures::stream::{Stream, StreamExt};
 use hyper::body::HttpBody as Body;
-use k8s_openapi::{Response, ResponseError};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::Status;
+use k8s_openapi::{ListableResource, Resource, Response, ResponseError, WatchEvent, WatchResponse};
 use snafu::{ResultExt, Snafu};
+use std::time::Duration;
 
 /// Converts the HTTP response [`Body`] to a stream of parsed Kubernetes
 /// [`Response`]s.
@@ -18,36 +20,161 @@ where
     B: Body,
     <B as Body>::Error: std::error::Error + 'static + Unpin,
 {
+    body_with_timeout(body, None)
+}
+
+/// Like [`body`], but terminates the stream with [`Error::IdleTimeout`] if
+/// no chunk arrives within `idle`.
+///
+/// Kubernetes watch connections can stall silently (a dropped load
+/// balancer, a half-open TCP connection) with no bytes arriving and no
+/// error ever surfacing, leaving the stream parked forever. `idle` bounds
+/// how long we wait for the next chunk before giving up, which matters
+/// because Kubernetes only guarantees periodic traffic on a watch when
+/// bookmarks are enabled. The timer is reset every time a chunk arrives.
+pub fn body_with_timeout<B, T>(
+    body: B,
+    idle: impl Into<Option<Duration>>,
+) -> impl Stream<Item = Result<T, Error<<B as Body>::Error>>>
+where
+    T: Response + Unpin + 'static,
+    B: Body,
+    <B as Body>::Error: std::error::Error + 'static + Unpin,
+{
+    let idle = idle.into();
     try_stream! {
         let mut decoder: MultiResponseDecoder<T> = MultiResponseDecoder::new();
 
         debug!(message = "streaming the HTTP body");
 
         pin_mut!(body);
-        while let Some(buf) = body.data().await {
+        loop {
+            let next = match idle {
+                Some(idle) => match tokio::time::timeout(idle, body.data()).await {
+                    Ok(next) => next,
+                    Err(_) => Err(Error::IdleTimeout)?,
+                },
+                None => body.data().await,
+            };
+            let buf = match next {
+                Some(buf) => buf,
+                None => break,
+            };
             let mut buf = buf.context(Reading)?;
-            let chunk = buf.to_bytes();
-            let responses = decoder.process_next_chunk(chunk.as_ref());
-            emit!(internal_events::ChunkProcessed{ byte_size: chunk.len() });
-            for response in responses {
-                // Sometimes Kubernetes API starts returning `null`s in
-                // the object field while streaming the response.
-                // Handle it as if the stream has ended.
-                // See https://github.com/kubernetes/client-go/issues/334
-                if let Err(ResponseError::Json(error)) = &response {
-                    if error.is_data() {
-                        warn!(message = "handling response json parsing data error as steram end", ?error);
-                        return;
+            // `buf` may be made up of several non-contiguous segments (e.g.
+            // a chain of chunks from the underlying transport). Feed each
+            // segment into the decoder as-is rather than calling
+            // `to_bytes()`, which would force an allocation and memcpy to
+            // make the whole thing contiguous first.
+            //
+            // This relies on `MultiResponseDecoder` already buffering a
+            // response internally across separate `process_next_chunk`
+            // calls when a response isn't complete yet (it has to: a
+            // response can already span multiple `body.data()` polls, e.g.
+            // `test_body_uses_finish` below feeds a lone `"{"` and expects
+            // the decoder to remember it). Calling it once per segment of a
+            // single poll, instead of once for the whole poll, exercises
+            // that same buffering more often but isn't a new requirement of
+            // it; `test_body_reassembles_response_split_across_segments`
+            // below pins this down directly.
+            let mut byte_size = 0;
+            while buf.has_remaining() {
+                let segment = buf.bytes();
+                let segment_len = segment.len();
+                let responses = decoder.process_next_chunk(segment);
+                byte_size += segment_len;
+                buf.advance(segment_len);
+                for response in responses {
+                    // Sometimes Kubernetes API starts returning `null`s in
+                    // the object field while streaming the response.
+                    // Handle it as if the stream has ended.
+                    // See https://github.com/kubernetes/client-go/issues/334
+                    if let Err(ResponseError::Json(error)) = &response {
+                        if error.is_data() {
+                            warn!(message = "handling response json parsing data error as steram end", ?error);
+                            return;
+                        }
                     }
+                    let response = response.context(Parsing)?;
+                    yield response;
                 }
-                let response = response.context(Parsing)?;
-                yield response;
             }
+            emit!(internal_events::ChunkProcessed{ byte_size });
         }
         decoder.finish().map_err(|data| Error::UnparsedDataUponCompletion { data })?;
     }
 }
 
+/// A single item produced by [`watch`].
+///
+/// A `BOOKMARK` watch event carries no real object payload, only a
+/// checkpoint of how far the server has progressed, so it's kept out of
+/// [`StreamItem::Event`] to avoid surprising consumers that only want to
+/// react to actual resource changes.
+#[derive(Debug)]
+pub enum StreamItem<T> {
+    /// A regular `ADDED`/`MODIFIED`/`DELETED`/`ERROR` watch event.
+    Event(WatchEvent<T>),
+    /// The `resourceVersion` carried by a `BOOKMARK` event. Callers should
+    /// remember the latest one seen and use it to resume the watch with
+    /// `resourceVersion=<last seen>` after a disconnect, instead of
+    /// relisting from scratch.
+    Bookmark(String),
+}
+
+/// Converts the HTTP response [`Body`] of a watch request to a stream of
+/// [`StreamItem`]s, splitting bookmark checkpoints out from real events.
+///
+/// This is a thin wrapper around [`body_with_timeout`] for callers that are
+/// watching a single resource type `R` and need to track `resourceVersion`
+/// bookmarks (see [`StreamItem`] for why bookmarks aren't folded into the
+/// event stream). `idle` is forwarded as-is; watches are exactly the case
+/// [`body_with_timeout`]'s idle timeout exists for, since bookmarks are
+/// what keeps an otherwise-quiet watch connection producing traffic.
+pub fn watch<B, R>(
+    body: B,
+    idle: impl Into<Option<Duration>>,
+) -> impl Stream<Item = Result<StreamItem<R>, Error<<B as Body>::Error>>>
+where
+    // `WatchEvent<R>`/`WatchResponse<R>` are only defined for resources
+    // that support being watched.
+    R: Resource + ListableResource + Unpin + 'static,
+    WatchResponse<R>: Response,
+    B: Body,
+    <B as Body>::Error: std::error::Error + 'static + Unpin,
+{
+    try_stream! {
+        let responses = self::body_with_timeout::<B, WatchResponse<R>>(body, idle);
+        pin_mut!(responses);
+
+        while let Some(response) = responses.next().await {
+            match response? {
+                WatchResponse::Ok(WatchEvent::Bookmark { resource_version }) => {
+                    yield StreamItem::Bookmark(resource_version);
+                }
+                WatchResponse::Ok(WatchEvent::ErrorStatus(status)) if is_desync(&status) => {
+                    Err(Error::DesyncRequired { status })?;
+                }
+                WatchResponse::Ok(event) => yield StreamItem::Event(event),
+                // Not a watch event we recognize (e.g. a plain `Status`
+                // that doesn't fit `WatchEvent`); nothing to surface.
+                WatchResponse::Other(_) => {}
+            }
+        }
+    }
+}
+
+/// Whether a watch `ERROR` event's `Status` indicates that the requested
+/// `resourceVersion` has been discarded by the API server (a `410 Gone`),
+/// meaning the watch can't simply be retried and a full relist is needed.
+fn is_desync(status: &Status) -> bool {
+    status.code == Some(410)
+        || status
+            .reason
+            .as_deref()
+            .map_or(false, |reason| reason.eq_ignore_ascii_case("expired") || reason.eq_ignore_ascii_case("gone"))
+}
+
 /// Errors that can occur in the stream.
 #[derive(Debug, Snafu)]
 pub enum Error<ReadError>
@@ -75,12 +202,28 @@ where
         /// The unparsed data.
         data: Vec<u8>,
     },
+
+    /// The API server reported that the requested `resourceVersion` is no
+    /// longer available (`410 Gone`/`Expired`). The watch can't be resumed
+    /// as-is; the caller must perform a full relist to get a fresh
+    /// `resourceVersion` before watching again.
+    #[snafu(display("resourceVersion is too old, a full relist is required"))]
+    DesyncRequired {
+        /// The `Status` the API server sent describing the desync.
+        status: Status,
+    },
+
+    /// No chunk was received within the configured idle timeout; the
+    /// connection is presumed dead and should be re-established.
+    #[snafu(display("no data received within the idle timeout"))]
+    IdleTimeout,
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::test_util;
+    use bytes05::Bytes;
     use futures::StreamExt;
     use k8s_openapi::{api::core::v1::Pod, WatchResponse};
 
@@ -190,4 +333,130 @@ mod tests {
             assert!(out_stream.next().await.is_none());
         })
     }
+
+    #[test]
+    fn test_watch_yields_bookmark_resource_version() {
+        test_util::trace_init();
+        test_util::block_on_std(async move {
+            let data = r#"{
+                "type": "BOOKMARK",
+                "object": {
+                    "kind": "Pod",
+                    "apiVersion": "v1",
+                    "metadata": {
+                        "resourceVersion": "12745"
+                    }
+                }
+            }"#;
+            let chunks: Vec<Result<_, std::io::Error>> = vec![Ok(data)];
+            let sample_body = hyper_body_from_chunks(chunks);
+
+            let out_stream = watch::<_, Pod>(sample_body, None);
+            pin_mut!(out_stream);
+
+            match out_stream.next().await.unwrap().unwrap() {
+                StreamItem::Bookmark(resource_version) => assert_eq!(resource_version, "12745"),
+                other => panic!("expected a bookmark, got {:?}", other),
+            }
+
+            assert!(out_stream.next().await.is_none());
+        })
+    }
+
+    #[test]
+    fn test_watch_signals_desync_on_410() {
+        test_util::trace_init();
+        test_util::block_on_std(async move {
+            let data = r#"{
+                "type": "ERROR",
+                "object": {
+                    "kind": "Status",
+                    "apiVersion": "v1",
+                    "status": "Failure",
+                    "reason": "Expired",
+                    "code": 410
+                }
+            }"#;
+            let chunks: Vec<Result<_, std::io::Error>> = vec![Ok(data)];
+            let sample_body = hyper_body_from_chunks(chunks);
+
+            let out_stream = watch::<_, Pod>(sample_body, None);
+            pin_mut!(out_stream);
+
+            {
+                let err = out_stream.next().await.unwrap().unwrap_err();
+                assert!(matches!(err, Error::DesyncRequired { .. }));
+            }
+
+            assert!(out_stream.next().await.is_none());
+        })
+    }
+
+    #[test]
+    fn test_body_with_timeout_errors_when_idle() {
+        test_util::trace_init();
+        test_util::block_on_std(async move {
+            let in_stream = futures::stream::pending::<Result<&'static str, std::io::Error>>();
+            let sample_body = hyper::body::Body::wrap_stream(in_stream);
+
+            let out_stream = body_with_timeout::<_, WatchResponse<Pod>>(
+                sample_body,
+                Duration::from_millis(10),
+            );
+            pin_mut!(out_stream);
+
+            let err = out_stream.next().await.unwrap().unwrap_err();
+            assert!(matches!(err, Error::IdleTimeout));
+        })
+    }
+
+    /// A body whose single chunk is a [`Chain`] of two [`Bytes`] segments,
+    /// so `process_next_chunk` gets fed each segment separately rather than
+    /// one contiguous buffer.
+    struct ChainedChunkBody(Option<bytes05::buf::Chain<Bytes, Bytes>>);
+
+    impl Body for ChainedChunkBody {
+        type Data = bytes05::buf::Chain<Bytes, Bytes>;
+        type Error = std::io::Error;
+
+        fn poll_data(
+            mut self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Option<Result<Self::Data, Self::Error>>> {
+            std::task::Poll::Ready(self.0.take().map(Ok))
+        }
+
+        fn poll_trailers(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<Result<Option<hyper::HeaderMap>, Self::Error>> {
+            std::task::Poll::Ready(Ok(None))
+        }
+    }
+
+    #[test]
+    fn test_body_reassembles_response_split_across_segments() {
+        test_util::trace_init();
+        test_util::block_on_std(async move {
+            let data = br#"{
+                "type": "ADDED",
+                "object": {
+                    "kind": "Pod",
+                    "apiVersion": "v1",
+                    "metadata": {
+                        "uid": "uid0"
+                    }
+                }
+            }"#;
+            let (first, second) = data.split_at(data.len() / 2);
+            let chained = Bytes::copy_from_slice(first).chain(Bytes::copy_from_slice(second));
+            let sample_body = ChainedChunkBody(Some(chained));
+
+            let out_stream = body::<_, WatchResponse<Pod>>(sample_body);
+            pin_mut!(out_stream);
+
+            assert!(out_stream.next().await.unwrap().is_ok());
+            assert!(out_stream.next().await.is_none());
+        })
+    }
 }